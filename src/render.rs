@@ -1,31 +1,71 @@
-use matrix_sdk::events::room::{
-    encrypted::EncryptedEvent,
-    member::{MemberEvent, MembershipState},
-    message::{
-        AudioMessageEventContent, EmoteMessageEventContent,
-        FileMessageEventContent, ImageMessageEventContent, MessageEvent,
-        MessageEventContent, NoticeMessageEventContent,
-        TextMessageEventContent, VideoMessageEventContent,
+mod html;
+mod media;
+
+use matrix_sdk::{
+    events::room::{
+        encrypted::EncryptedEvent,
+        member::{MemberEvent, MemberEventContent, MembershipState},
+        message::{
+            AudioMessageEventContent, EmoteMessageEventContent,
+            FileMessageEventContent, ImageMessageEventContent, MessageEvent,
+            MessageEventContent, NoticeMessageEventContent, Relation,
+            TextMessageEventContent, VideoMessageEventContent,
+        },
+        EncryptedFile,
     },
+    identifiers::EventId,
 };
 
 /// This trait describes events that can be rendered in the weechat UI
 pub(crate) trait RenderableEvent {
     /// Convert the event into a string that will be displayed in the UI.
     /// The displayname is taken as a parameter since it cannot be calculated from the event
-    /// context alone.
-    fn render(&self, displayname: &str) -> String;
+    /// context alone. The homeserver URL is needed to turn `mxc://` media references into
+    /// downloadable links. `reply_to_sender` is the displayname of the sender of the event this
+    /// one replies to (if any and if the caller could resolve it), also not calculable from the
+    /// event alone.
+    fn render(
+        &self,
+        displayname: &str,
+        homeserver: &str,
+        reply_to_sender: Option<&str>,
+    ) -> String;
 }
 
 impl RenderableEvent for EncryptedEvent {
     // TODO: this is not implemented yet
-    fn render(&self, displayname: &str) -> String {
+    fn render(
+        &self,
+        displayname: &str,
+        _homeserver: &str,
+        _reply_to_sender: Option<&str>,
+    ) -> String {
         format!("{}\t{}", displayname, "Unable to decrypt message")
     }
 }
 
 impl RenderableEvent for MemberEvent {
-    fn render(&self, displayname: &str) -> String {
+    fn render(
+        &self,
+        displayname: &str,
+        _homeserver: &str,
+        _reply_to_sender: Option<&str>,
+    ) -> String {
+        let prev_membership = self.prev_content.as_ref().map(|prev| prev.membership);
+
+        if self.content.membership == MembershipState::Join
+            && prev_membership == Some(MembershipState::Join)
+        {
+            if let Some(rendered) = render_profile_change(
+                displayname,
+                &self.state_key,
+                &self.content,
+                self.prev_content.as_ref(),
+            ) {
+                return rendered;
+            }
+        }
+
         let operation = match self.content.membership {
             MembershipState::Join => "joined",
             MembershipState::Leave => "left",
@@ -40,29 +80,71 @@ impl RenderableEvent for MemberEvent {
     }
 }
 
+/// Diff a `Join` -> `Join` membership event's `content` against its `prev_content` to report a
+/// display-name or avatar change instead of a spurious "joined the room" line. Returns `None`
+/// when neither actually changed.
+fn render_profile_change(
+    displayname: &str,
+    user_id: &str,
+    content: &MemberEventContent,
+    prev_content: Option<&MemberEventContent>,
+) -> Option<String> {
+    let prev_displayname = prev_content.and_then(|prev| prev.displayname.as_deref());
+    let prev_avatar_url = prev_content.and_then(|prev| prev.avatar_url.as_deref());
+
+    if content.displayname.as_deref() != prev_displayname {
+        return Some(match (prev_displayname, content.displayname.as_deref()) {
+            (Some(old), Some(new)) => format!("{} is now known as {}", old, new),
+            (None, Some(new)) => {
+                format!("{} ({}) set their display name to {}", displayname, user_id, new)
+            }
+            (Some(old), None) => {
+                format!("{} ({}) is no longer known as {}", displayname, user_id, old)
+            }
+            (None, None) => return None,
+        });
+    }
+
+    if content.avatar_url.as_deref() != prev_avatar_url {
+        return Some(format!("{} ({}) changed their avatar", displayname, user_id));
+    }
+
+    None
+}
+
 impl RenderableEvent for MessageEvent {
-    fn render(&self, displayname: &str) -> String {
+    fn render(
+        &self,
+        displayname: &str,
+        homeserver: &str,
+        reply_to_sender: Option<&str>,
+    ) -> String {
         use MessageEventContent::*;
 
         match &self.content {
-            Text(t) => format!("{}\t{}", displayname, t.resolve_body()),
-            Emote(e) => format!("{}\t{}", displayname, e.resolve_body()),
-            Audio(a) => {
-                format!("{}\t{}: {}", displayname, a.body, a.resolve_url())
-            }
-            File(f) => {
-                format!("{}\t{}: {}", displayname, f.body, f.resolve_url())
-            }
-            Image(i) => {
-                format!("{}\t{}: {}", displayname, i.body, i.resolve_url())
-            }
-            Location(l) => {
-                format!("{}\t{}: {}", displayname, l.body, l.geo_uri)
-            }
-            Notice(n) => format!("{}\t{}", displayname, n.resolve_body()),
-            Video(v) => {
-                format!("{}\t{}: {}", displayname, v.body, v.resolve_url())
-            }
+            Text(t) => format!(
+                "{}{}\t{}",
+                reply_prefix(t.in_reply_to(), reply_to_sender),
+                displayname,
+                t.resolve_body()
+            ),
+            Emote(e) => format!(
+                "{}{}\t{}",
+                reply_prefix(e.in_reply_to(), reply_to_sender),
+                displayname,
+                e.resolve_body()
+            ),
+            Audio(a) => render_media(displayname, a, homeserver),
+            File(f) => render_media(displayname, f, homeserver),
+            Image(i) => render_media(displayname, i, homeserver),
+            Location(l) => render_location(displayname, &l.body, &l.geo_uri),
+            Notice(n) => format!(
+                "{}{}\t{}",
+                reply_prefix(n.in_reply_to(), reply_to_sender),
+                displayname,
+                n.resolve_body()
+            ),
+            Video(v) => render_media(displayname, v, homeserver),
             ServerNotice(sn) => {
                 format!("SERVER\t{}", sn.body) // TODO
             }
@@ -71,18 +153,84 @@ impl RenderableEvent for MessageEvent {
 }
 
 /// Trait for message event types that contain an optional formatted body. `resolve_body` will
-/// return the formatted body if present, else fallback to the regular body.
+/// render the formatted body (if present) into weechat inline formatting codes, else fallback to
+/// the regular body. If the event is a rich reply, the quoted rich-reply fallback is stripped
+/// from whichever body is used, before it is rendered.
 trait HasFormattedBody {
     fn body(&self) -> &str;
     fn formatted_body(&self) -> Option<&str>;
+    fn relates_to(&self) -> Option<&Relation>;
+
+    /// The event this message is a rich reply to, if any.
     #[inline]
-    fn resolve_body(&self) -> &str {
-        self.formatted_body().unwrap_or_else(|| self.body())
+    fn in_reply_to(&self) -> Option<&EventId> {
+        match self.relates_to()? {
+            Relation::Reply { in_reply_to } => Some(&in_reply_to.event_id),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    fn resolve_body(&self) -> String {
+        let is_reply = self.in_reply_to().is_some();
+        match self.formatted_body() {
+            Some(formatted) => {
+                let formatted = if is_reply {
+                    html::remove_reply_fallback(formatted)
+                } else {
+                    formatted.to_string()
+                };
+                html::render(&formatted)
+            }
+            None => {
+                let body = self.body();
+                if is_reply {
+                    remove_plain_reply_fallback(body).to_string()
+                } else {
+                    body.to_string()
+                }
+            }
+        }
+    }
+}
+
+/// Drop the leading run of `> ` quote lines (plus the blank line that follows them) that ruma's
+/// clients prepend to the plain `body` of a rich reply.
+fn remove_plain_reply_fallback(body: &str) -> &str {
+    let mut rest = body;
+    let mut stripped_any = false;
+    while let Some(after) = rest.strip_prefix("> ") {
+        stripped_any = true;
+        rest = match after.find('\n') {
+            Some(newline) => &after[newline + 1..],
+            None => "",
+        };
+    }
+    if stripped_any {
+        rest.strip_prefix('\n').unwrap_or(rest)
+    } else {
+        body
+    }
+}
+
+/// A compact `↪ replying to <sender>` line prepended to a rendered reply. It is emitted as its
+/// own fully-formed weechat line (empty prefix column, then the message) so it doesn't get
+/// merged into the following line's prefix column by weechat's `\t`-based column split. Falls
+/// back to the raw event id when the caller couldn't resolve the replied-to event's sender.
+fn reply_prefix(in_reply_to: Option<&EventId>, reply_to_sender: Option<&str>) -> String {
+    match in_reply_to {
+        Some(event_id) => {
+            let sender = reply_to_sender
+                .map(str::to_string)
+                .unwrap_or_else(|| event_id.to_string());
+            format!("\t\u{21aa} replying to {}\n", sender)
+        }
+        None => String::new(),
     }
 }
 
 // Repeating this for each event type would get boring fast so lets use a simple macro to implement
-// the trait for a struct that has a `body` and `formatted_body` field
+// the trait for a struct that has a `body`, `formatted_body` and `relates_to` field
 macro_rules! has_formatted_body {
     ($content: ident) => {
         impl HasFormattedBody for $content {
@@ -95,6 +243,11 @@ macro_rules! has_formatted_body {
             fn formatted_body(&self) -> Option<&str> {
                 self.formatted_body.as_deref()
             }
+
+            #[inline]
+            fn relates_to(&self) -> Option<&Relation> {
+                self.relates_to.as_ref()
+            }
         }
     };
 }
@@ -104,15 +257,62 @@ macro_rules! has_formatted_body {
 trait HasUrlOrFile {
     fn url(&self) -> Option<&str>;
     fn file(&self) -> Option<&str>;
+    fn encrypted_file(&self) -> Option<&EncryptedFile>;
+    fn body(&self) -> &str;
+    fn filename(&self) -> Option<&str>;
+    fn formatted_body(&self) -> Option<&str>;
+
     #[inline]
     fn resolve_url(&self) -> &str {
         // the file is either encrypted or not encrypted so either `url` or `file` must
         // exist and unwrapping will never panic
         self.url().or_else(|| self.file()).unwrap()
     }
+
+    /// Resolve this attachment's `mxc://` reference into a downloadable HTTP(S) URL served by
+    /// `homeserver`, carrying along decryption key material for encrypted rooms.
+    #[inline]
+    fn resolve_media(&self, homeserver: &str) -> Option<media::ResolvedMedia> {
+        match self.encrypted_file() {
+            Some(file) => {
+                media::resolve_encrypted(homeserver, file, media::MediaFormat::File)
+            }
+            None => media::resolve_url(homeserver, self.url()?, media::MediaFormat::File),
+        }
+    }
+
+    /// Per MSC2530, `body` is a caption describing the attachment rather than just its filename
+    /// when an explicit `filename` differs from it, or when a `formatted_body` is present.
+    #[inline]
+    fn is_caption(&self) -> bool {
+        match self.filename() {
+            Some(filename) => self.body() != filename || self.formatted_body().is_some(),
+            None => self.formatted_body().is_some(),
+        }
+    }
+
+    /// The caption to display, if any, rendering `formatted_body` through the HTML renderer
+    /// when present.
+    #[inline]
+    fn resolve_caption(&self) -> Option<String> {
+        if !self.is_caption() {
+            return None;
+        }
+        Some(match self.formatted_body() {
+            Some(formatted) => html::render(formatted),
+            None => self.body().to_string(),
+        })
+    }
+
+    /// The filename to show in the secondary `📎 filename` annotation.
+    #[inline]
+    fn display_filename(&self) -> &str {
+        self.filename().unwrap_or_else(|| self.body())
+    }
 }
 
-// Same as above: a simple macro to implement the trait for structs with `url` and `file` fields.
+// Same as above: a simple macro to implement the trait for structs with `url`, `file`, `body`,
+// `filename` and `formatted_body` fields.
 macro_rules! has_url_or_file {
     ($content: ident) => {
         impl HasUrlOrFile for $content {
@@ -125,8 +325,98 @@ macro_rules! has_url_or_file {
             fn file(&self) -> Option<&str> {
                 self.file.as_ref().map(|f| f.url.as_str())
             }
+
+            #[inline]
+            fn encrypted_file(&self) -> Option<&EncryptedFile> {
+                self.file.as_ref()
+            }
+
+            #[inline]
+            fn body(&self) -> &str {
+                &self.body
+            }
+
+            #[inline]
+            fn filename(&self) -> Option<&str> {
+                self.filename.as_deref()
+            }
+
+            #[inline]
+            fn formatted_body(&self) -> Option<&str> {
+                self.formatted_body.as_deref()
+            }
+        }
+    };
+}
+
+/// Render an attachment (`Image`/`Audio`/`Video`/`File`): an MSC2530 caption (if any) as the
+/// main line, with the filename and a downloadable URL shown as a secondary annotation. Falls
+/// back to the raw `mxc://` reference if it can't be resolved against `homeserver`.
+fn render_media(displayname: &str, content: &impl HasUrlOrFile, homeserver: &str) -> String {
+    let filename = content.display_filename();
+    let url = match content.resolve_media(homeserver) {
+        Some(resolved) if resolved.is_encrypted() => {
+            format!("{} (encrypted)", resolved.url)
         }
+        Some(resolved) => resolved.url,
+        None => content.resolve_url().to_string(),
     };
+    match content.resolve_caption() {
+        Some(caption) => format!(
+            "{}\t{}\n\u{1F4CE} {}: {}",
+            displayname, caption, filename, url
+        ),
+        None => format!("{}\t{}: {}", displayname, filename, url),
+    }
+}
+
+/// A coordinate parsed from an RFC 5870 `geo:` URI, with its optional location uncertainty.
+struct GeoUri {
+    latitude: f64,
+    longitude: f64,
+    uncertainty: Option<f64>,
+}
+
+/// Parse a `geo:lat,lon;u=uncertainty` URI, as found in `m.location` events.
+fn parse_geo_uri(geo_uri: &str) -> Option<GeoUri> {
+    let rest = geo_uri.strip_prefix("geo:")?;
+    let (coords, params) = match rest.find(';') {
+        Some(idx) => (&rest[..idx], &rest[idx + 1..]),
+        None => (rest, ""),
+    };
+    let mut parts = coords.splitn(2, ',');
+    let latitude: f64 = parts.next()?.trim().parse().ok()?;
+    let longitude: f64 = parts.next()?.trim().parse().ok()?;
+    let uncertainty = params
+        .split(';')
+        .find_map(|param| param.strip_prefix("u="))
+        .and_then(|u| u.trim().parse().ok());
+
+    Some(GeoUri { latitude, longitude, uncertainty })
+}
+
+/// Render a `Location` message: the body, the parsed coordinates, and a clickable OpenStreetMap
+/// link. Falls back to the raw `geo:` URI if it can't be parsed.
+fn render_location(displayname: &str, body: &str, geo_uri: &str) -> String {
+    match parse_geo_uri(geo_uri) {
+        Some(geo) => {
+            let uncertainty = geo
+                .uncertainty
+                .map(|u| format!(" (±{}m)", u))
+                .unwrap_or_default();
+            format!(
+                "{}\t\u{1F4CD} {} ({:.4}, {:.4}){}: https://www.openstreetmap.org/?mlat={lat}&mlon={lon}#map=16/{lat}/{lon}",
+                displayname,
+                body,
+                geo.latitude,
+                geo.longitude,
+                uncertainty,
+                lat = geo.latitude,
+                lon = geo.longitude,
+            )
+        }
+        None => format!("{}\t{}: {}", displayname, body, geo_uri),
+    }
 }
 
 // this actually implements the trait for different event types
@@ -0,0 +1,494 @@
+//! Renderer for the Matrix HTML message subset (`org.matrix.custom.html`) into
+//! weechat inline formatting codes.
+//!
+//! Only the tag allowlist from the [Matrix spec] is honoured: `b`/`strong`,
+//! `i`/`em`, `u`, `s`/`del`, `code`, `pre`, `a[href]`, `font`/`span` with
+//! `color`/`data-mx-color`, `br`, `blockquote`, `ul`/`ol`/`li`, `h1`-`h6` and
+//! `mx-reply`. Anything else is dropped while keeping its text children,
+//! matching the sanitization posture of ruma's `sanitize_html`.
+//!
+//! [Matrix spec]: https://spec.matrix.org/latest/client-server-api/#mroommessage-msgtypes
+
+/// Bold on/off.
+const BOLD: &str = "\x02";
+/// Italic on/off.
+const ITALIC: &str = "\x1D";
+/// Underline on/off.
+const UNDERLINE: &str = "\x1F";
+/// Reset all attributes (weechat has no per-attribute "off" code).
+const RESET: &str = "\x0F";
+
+/// Render a Matrix `formatted_body` into weechat inline formatting codes.
+pub(crate) fn render(html: &str) -> String {
+    let mut renderer = Renderer::default();
+    renderer.run(html);
+    renderer.output
+}
+
+/// Remove the `<mx-reply>...</mx-reply>` element wrapping the rich-reply fallback from a
+/// `formatted_body`, mirroring ruma's `RemoveReplyFallback`.
+pub(crate) fn remove_reply_fallback(html: &str) -> String {
+    match (html.find("<mx-reply>"), html.find("</mx-reply>")) {
+        (Some(start), Some(end)) if end > start => {
+            let after = end + "</mx-reply>".len();
+            format!("{}{}", &html[..start], &html[after..])
+        }
+        _ => html.to_string(),
+    }
+}
+
+/// A tag that carries weechat formatting to apply on open and undo on close.
+enum OpenTag {
+    Bold,
+    Italic,
+    Underline,
+    Strike,
+    Code,
+    Pre,
+    Anchor { href: Option<String> },
+    Color { code: Option<&'static str> },
+    Heading,
+    Blockquote,
+    List { ordered: bool, index: usize },
+    ListItem,
+    /// Recognized but carries no formatting of its own (e.g. `mx-reply`, or
+    /// any tag outside the allowlist).
+    Transparent,
+}
+
+#[derive(Default)]
+struct Renderer {
+    output: String,
+    /// Tags currently open, innermost last.
+    stack: Vec<OpenTag>,
+    /// Number of currently open `<blockquote>` elements.
+    blockquote_depth: usize,
+    /// Set right after a newline (or at the start of the output) so the next
+    /// text emitted can be prefixed with the current blockquote markers.
+    at_line_start: bool,
+}
+
+impl Renderer {
+    fn run(&mut self, html: &str) {
+        self.at_line_start = true;
+        let mut rest = html;
+        while let Some(lt) = rest.find('<') {
+            self.push_text(&rest[..lt]);
+            rest = &rest[lt..];
+            match parse_tag(rest) {
+                Some((tag, consumed)) => {
+                    self.handle_tag(tag);
+                    rest = &rest[consumed..];
+                }
+                None => {
+                    // Not a real tag (e.g. a lone `<`); emit it literally.
+                    self.push_text("<");
+                    rest = &rest[1..];
+                }
+            }
+        }
+        self.push_text(rest);
+    }
+
+    fn handle_tag(&mut self, tag: ParsedTag) {
+        match tag {
+            ParsedTag::Start { name, attrs, self_closing } => {
+                let open = match name.as_str() {
+                    "b" | "strong" => {
+                        self.push_str(BOLD);
+                        OpenTag::Bold
+                    }
+                    "i" | "em" => {
+                        self.push_str(ITALIC);
+                        OpenTag::Italic
+                    }
+                    "u" => {
+                        self.push_str(UNDERLINE);
+                        OpenTag::Underline
+                    }
+                    "s" | "del" => {
+                        self.push_str("~");
+                        OpenTag::Strike
+                    }
+                    "code" => {
+                        self.push_str("`");
+                        OpenTag::Code
+                    }
+                    "pre" => {
+                        if !self.at_line_start {
+                            self.newline();
+                        }
+                        self.push_text("```");
+                        self.newline();
+                        OpenTag::Pre
+                    }
+                    "br" => {
+                        self.newline();
+                        return;
+                    }
+                    "a" => {
+                        let href = attrs.get("href").cloned();
+                        OpenTag::Anchor { href }
+                    }
+                    "font" | "span" => {
+                        let color = attrs
+                            .get("data-mx-color")
+                            .or_else(|| attrs.get("color"))
+                            .and_then(|hex| weechat_color_code(hex));
+                        if let Some(code) = color {
+                            self.push_str(code);
+                        }
+                        OpenTag::Color { code: color }
+                    }
+                    "blockquote" => {
+                        self.blockquote_depth += 1;
+                        if !self.at_line_start {
+                            self.newline();
+                        }
+                        OpenTag::Blockquote
+                    }
+                    "ul" => OpenTag::List { ordered: false, index: 0 },
+                    "ol" => OpenTag::List { ordered: true, index: 0 },
+                    "li" => {
+                        if !self.at_line_start {
+                            self.newline();
+                        }
+                        let marker = match self.stack.iter_mut().rev().find_map(|t| match t {
+                            OpenTag::List { ordered, index } => {
+                                *index += 1;
+                                Some((*ordered, *index))
+                            }
+                            _ => None,
+                        }) {
+                            Some((true, n)) => format!("{}. ", n),
+                            _ => "\u{2022} ".to_string(),
+                        };
+                        self.push_text(&marker);
+                        OpenTag::ListItem
+                    }
+                    "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                        if !self.at_line_start {
+                            self.newline();
+                        }
+                        self.push_str(BOLD);
+                        OpenTag::Heading
+                    }
+                    "mx-reply" => OpenTag::Transparent,
+                    _ => OpenTag::Transparent,
+                };
+                if self_closing {
+                    self.close(open);
+                } else {
+                    self.stack.push(open);
+                }
+            }
+            ParsedTag::End { name } => {
+                // `tag_name` has one spelling per tag; map the aliases onto it.
+                let name = match name.as_str() {
+                    "strong" => "b",
+                    "em" => "i",
+                    "del" => "s",
+                    "font" => "span",
+                    "ol" => "ul",
+                    "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => "h",
+                    other => other,
+                };
+                if let Some(pos) = self.stack.iter().rposition(|t| tag_name(t) == name) {
+                    // Close everything up to and including the matching tag;
+                    // unclosed inner tags (malformed HTML) are dropped too.
+                    while self.stack.len() > pos {
+                        let top = self.stack.pop().unwrap();
+                        self.close(top);
+                    }
+                }
+            }
+            ParsedTag::Comment => {}
+        }
+    }
+
+    fn close(&mut self, tag: OpenTag) {
+        match tag {
+            OpenTag::Bold | OpenTag::Italic | OpenTag::Underline | OpenTag::Color { .. }
+            | OpenTag::Heading => {
+                self.push_str(RESET);
+                self.reapply_open_formatting();
+            }
+            OpenTag::Strike => self.push_str("~"),
+            OpenTag::Code => self.push_str("`"),
+            OpenTag::Anchor { href: Some(href) } => {
+                self.push_text(&format!(" ({})", href));
+            }
+            OpenTag::Anchor { href: None } => {}
+            OpenTag::Blockquote => {
+                self.blockquote_depth = self.blockquote_depth.saturating_sub(1);
+                self.newline();
+            }
+            OpenTag::Pre => {
+                if !self.at_line_start {
+                    self.newline();
+                }
+                self.push_text("```");
+                self.newline();
+            }
+            OpenTag::List { .. } | OpenTag::ListItem | OpenTag::Transparent => {}
+        }
+    }
+
+    /// After a reset, weechat has lost every attribute, so outer still-open
+    /// tags need to be reapplied.
+    fn reapply_open_formatting(&mut self) {
+        let codes: Vec<&'static str> = self
+            .stack
+            .iter()
+            .filter_map(|t| match t {
+                OpenTag::Bold => Some(BOLD),
+                OpenTag::Italic => Some(ITALIC),
+                OpenTag::Underline => Some(UNDERLINE),
+                OpenTag::Color { code: Some(c) } => Some(*c),
+                _ => None,
+            })
+            .collect();
+        for code in codes {
+            self.output.push_str(code);
+        }
+    }
+
+    fn newline(&mut self) {
+        self.output.push('\n');
+        self.at_line_start = true;
+    }
+
+    fn push_str(&mut self, s: &str) {
+        self.output.push_str(s);
+    }
+
+    /// Emit decoded text, inserting the blockquote prefix right after a
+    /// newline so every quoted line (not just the first) is marked.
+    fn push_text(&mut self, raw: &str) {
+        if raw.is_empty() {
+            return;
+        }
+        let text = decode_entities(raw);
+        if self.at_line_start && self.blockquote_depth > 0 {
+            self.output.push_str(&"> ".repeat(self.blockquote_depth));
+        }
+        self.at_line_start = false;
+        self.output.push_str(&text);
+    }
+}
+
+fn tag_name(tag: &OpenTag) -> &'static str {
+    match tag {
+        OpenTag::Bold => "b",
+        OpenTag::Italic => "i",
+        OpenTag::Underline => "u",
+        OpenTag::Strike => "s",
+        OpenTag::Code => "code",
+        OpenTag::Pre => "pre",
+        OpenTag::Anchor { .. } => "a",
+        OpenTag::Color { .. } => "span",
+        OpenTag::Heading => "h",
+        OpenTag::Blockquote => "blockquote",
+        OpenTag::List { .. } => "ul",
+        OpenTag::ListItem => "li",
+        OpenTag::Transparent => "",
+    }
+}
+
+enum ParsedTag {
+    Start {
+        name: String,
+        attrs: std::collections::HashMap<String, String>,
+        self_closing: bool,
+    },
+    End {
+        name: String,
+    },
+    Comment,
+}
+
+/// Parse a single tag (or comment) starting at `input[0] == '<'`. Returns the
+/// parsed tag and the number of bytes consumed, or `None` if `input` doesn't
+/// start with a well-formed tag.
+fn parse_tag(input: &str) -> Option<(ParsedTag, usize)> {
+    debug_assert!(input.starts_with('<'));
+    if input.starts_with("<!--") {
+        let end = input.find("-->")?;
+        return Some((ParsedTag::Comment, end + 3));
+    }
+    let end = input.find('>')?;
+    let inner = &input[1..end];
+    let consumed = end + 1;
+    if let Some(name) = inner.strip_prefix('/') {
+        return Some((
+            ParsedTag::End { name: name.trim().to_ascii_lowercase() },
+            consumed,
+        ));
+    }
+    let trimmed = inner.trim_end();
+    let self_closing = trimmed.ends_with('/');
+    let inner = trimmed.trim_end_matches('/').trim_end();
+    let mut split = inner.splitn(2, char::is_whitespace);
+    let name = split.next()?.to_ascii_lowercase();
+    let tail = split.next().unwrap_or("");
+    Some((
+        ParsedTag::Start {
+            name,
+            attrs: parse_attrs(tail),
+            self_closing,
+        },
+        consumed,
+    ))
+}
+
+fn parse_attrs(tail: &str) -> std::collections::HashMap<String, String> {
+    let mut attrs = std::collections::HashMap::new();
+    let mut rest = tail.trim();
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].trim().trim_end_matches('/').to_ascii_lowercase();
+        if key.is_empty() {
+            break;
+        }
+        rest = rest[eq + 1..].trim_start();
+        let (value, after) = if let Some(quoted) = rest.strip_prefix('"') {
+            match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            }
+        } else if let Some(quoted) = rest.strip_prefix('\'') {
+            match quoted.find('\'') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            }
+        } else {
+            match rest.find(char::is_whitespace) {
+                Some(end) => (&rest[..end], &rest[end..]),
+                None => (rest, ""),
+            }
+        };
+        if !key.is_empty() {
+            attrs.insert(key, decode_entities(value));
+        }
+        rest = after.trim_start();
+    }
+    attrs
+}
+
+/// Decode the small set of HTML entities that show up in Matrix bodies:
+/// named entities plus decimal/hex numeric references.
+fn decode_entities(input: &str) -> String {
+    if !input.contains('&') {
+        return input.to_string();
+    }
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        // Entities are short ASCII runs (`&amp;`, `&#x1F600;`); bound the search so a
+        // stray `&` in front of a long run of text doesn't force scanning the whole
+        // rest of the string, without byte-slicing into the middle of a multibyte char.
+        let lookahead_end = rest
+            .char_indices()
+            .map(|(i, c)| i + c.len_utf8())
+            .find(|&i| i > 12)
+            .unwrap_or_else(|| rest.len());
+        if let Some(semi) = rest[..lookahead_end].find(';') {
+            let entity = &rest[1..semi];
+            let decoded = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{a0}'),
+                _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+                    u32::from_str_radix(&entity[2..], 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                }
+                _ if entity.starts_with('#') => {
+                    entity[1..].parse::<u32>().ok().and_then(char::from_u32)
+                }
+                _ => None,
+            };
+            match decoded {
+                Some(c) => {
+                    out.push(c);
+                    rest = &rest[semi + 1..];
+                }
+                None => {
+                    out.push('&');
+                    rest = &rest[1..];
+                }
+            }
+        } else {
+            out.push('&');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The 16 basic weechat/ANSI colors, used to find the nearest match for an
+/// arbitrary `color`/`data-mx-color` hex value.
+const WEECHAT_PALETTE: [(u8, u8, u8, u8); 16] = [
+    (0, 0x00, 0x00, 0x00),
+    (1, 0x80, 0x00, 0x00),
+    (2, 0x00, 0x80, 0x00),
+    (3, 0x80, 0x80, 0x00),
+    (4, 0x00, 0x00, 0x80),
+    (5, 0x80, 0x00, 0x80),
+    (6, 0x00, 0x80, 0x80),
+    (7, 0xc0, 0xc0, 0xc0),
+    (8, 0x80, 0x80, 0x80),
+    (9, 0xff, 0x00, 0x00),
+    (10, 0x00, 0xff, 0x00),
+    (11, 0xff, 0xff, 0x00),
+    (12, 0x00, 0x00, 0xff),
+    (13, 0xff, 0x00, 0xff),
+    (14, 0x00, 0xff, 0xff),
+    (15, 0xff, 0xff, 0xff),
+];
+
+/// Map a CSS hex color (`#rrggbb` or `rrggbb`) to the weechat color code
+/// (`\x03NN`) of the nearest entry in the basic palette.
+fn weechat_color_code(hex: &str) -> Option<&'static str> {
+    let hex = hex.trim().trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+    let r = u8::from_str_radix(&hex[0..2], 16).ok()?;
+    let g = u8::from_str_radix(&hex[2..4], 16).ok()?;
+    let b = u8::from_str_radix(&hex[4..6], 16).ok()?;
+    let nearest = WEECHAT_PALETTE
+        .iter()
+        .min_by_key(|(_, pr, pg, pb)| {
+            let dr = i32::from(r) - i32::from(*pr);
+            let dg = i32::from(g) - i32::from(*pg);
+            let db = i32::from(b) - i32::from(*pb);
+            dr * dr + dg * dg + db * db
+        })
+        .map(|(code, _, _, _)| *code)?;
+    // Return one of a fixed set of "\x03NN" literals so callers don't need to
+    // carry an owned String around just to toggle a color.
+    Some(match nearest {
+        0 => "\x0300",
+        1 => "\x0301",
+        2 => "\x0302",
+        3 => "\x0303",
+        4 => "\x0304",
+        5 => "\x0305",
+        6 => "\x0306",
+        7 => "\x0307",
+        8 => "\x0308",
+        9 => "\x0309",
+        10 => "\x0310",
+        11 => "\x0311",
+        12 => "\x0312",
+        13 => "\x0313",
+        14 => "\x0314",
+        _ => "\x0315",
+    })
+}
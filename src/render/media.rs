@@ -0,0 +1,135 @@
+//! Resolve `mxc://` media references into downloadable HTTP(S) URLs, modeled on matrix-sdk's
+//! `MediaFormat`/`MediaThumbnailSize` design.
+
+use matrix_sdk::events::room::EncryptedFile;
+
+/// How a thumbnail should be produced, mirroring the `method` query parameter of the
+/// `/thumbnail` media endpoint.
+// TODO: unused until we render thumbnails instead of linking to the original file.
+#[allow(dead_code)]
+pub(crate) enum ThumbnailMethod {
+    Crop,
+    Scale,
+}
+
+impl ThumbnailMethod {
+    #[allow(dead_code)]
+    fn as_query_value(&self) -> &'static str {
+        match self {
+            ThumbnailMethod::Crop => "crop",
+            ThumbnailMethod::Scale => "scale",
+        }
+    }
+}
+
+/// The size and cropping behaviour requested for a thumbnail, mirroring matrix-sdk's
+/// `MediaThumbnailSize`.
+// TODO: unused until we render thumbnails instead of linking to the original file.
+#[allow(dead_code)]
+pub(crate) struct MediaThumbnailSize {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) method: ThumbnailMethod,
+}
+
+/// Which representation of a piece of media to resolve a URL for, mirroring matrix-sdk's
+/// `MediaFormat`.
+pub(crate) enum MediaFormat {
+    /// The original file.
+    File,
+    /// A server-generated thumbnail of the given size.
+    // TODO: unused until we render thumbnails instead of linking to the original file.
+    #[allow(dead_code)]
+    Thumbnail(MediaThumbnailSize),
+}
+
+/// The AES-CTR key material needed to decrypt an `m.file` attachment, taken from its
+/// `EncryptedFile`'s JWK `key`/`iv`, plus the SHA-256 digest to verify the download against.
+// TODO: unused until we actually download and decrypt attachments instead of just linking to them.
+#[allow(dead_code)]
+pub(crate) struct EncryptionInfo {
+    /// Base64-encoded (unpadded, URL-safe) AES-CTR key, i.e. the JWK `k` parameter.
+    pub(crate) key: String,
+    /// Base64-encoded AES-CTR initialization vector.
+    pub(crate) iv: String,
+    /// Base64-encoded SHA-256 digest of the ciphertext, when provided.
+    pub(crate) sha256: Option<String>,
+}
+
+/// A media reference resolved to a downloadable HTTP(S) URL, plus the key material needed to
+/// decrypt it when it came from an encrypted room.
+pub(crate) struct ResolvedMedia {
+    pub(crate) url: String,
+    pub(crate) encryption: Option<EncryptionInfo>,
+}
+
+impl ResolvedMedia {
+    #[inline]
+    pub(crate) fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+}
+
+/// Split an `mxc://server/media_id` URI into its two components.
+fn parse_mxc(mxc: &str) -> Option<(&str, &str)> {
+    let rest = mxc.strip_prefix("mxc://")?;
+    let mut parts = rest.splitn(2, '/');
+    let server = parts.next()?;
+    let media_id = parts.next()?;
+    if server.is_empty() || media_id.is_empty() {
+        None
+    } else {
+        Some((server, media_id))
+    }
+}
+
+fn build_url(homeserver: &str, server: &str, media_id: &str, format: &MediaFormat) -> String {
+    let homeserver = homeserver.trim_end_matches('/');
+    match format {
+        MediaFormat::File => format!(
+            "{}/_matrix/media/r0/download/{}/{}",
+            homeserver, server, media_id
+        ),
+        MediaFormat::Thumbnail(size) => format!(
+            "{}/_matrix/media/r0/thumbnail/{}/{}?width={}&height={}&method={}",
+            homeserver,
+            server,
+            media_id,
+            size.width,
+            size.height,
+            size.method.as_query_value()
+        ),
+    }
+}
+
+/// Resolve a plain (unencrypted) `mxc://` URL into a downloadable HTTP(S) URL served by
+/// `homeserver` (e.g. `https://matrix.org`).
+pub(crate) fn resolve_url(
+    homeserver: &str,
+    mxc: &str,
+    format: MediaFormat,
+) -> Option<ResolvedMedia> {
+    let (server, media_id) = parse_mxc(mxc)?;
+    Some(ResolvedMedia {
+        url: build_url(homeserver, server, media_id, &format),
+        encryption: None,
+    })
+}
+
+/// Resolve an `m.file` encrypted attachment into a downloadable HTTP(S) URL, carrying along the
+/// key material a later download step needs to decrypt it.
+pub(crate) fn resolve_encrypted(
+    homeserver: &str,
+    file: &EncryptedFile,
+    format: MediaFormat,
+) -> Option<ResolvedMedia> {
+    let (server, media_id) = parse_mxc(&file.url)?;
+    Some(ResolvedMedia {
+        url: build_url(homeserver, server, media_id, &format),
+        encryption: Some(EncryptionInfo {
+            key: file.key.k.clone(),
+            iv: file.iv.clone(),
+            sha256: file.hashes.get("sha256").map(ToString::to_string),
+        }),
+    })
+}